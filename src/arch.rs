@@ -0,0 +1,49 @@
+//! Architecture-specific bits needed to safely jump into freshly copied executable code.
+//!
+//! x86_64 has a coherent instruction cache, so a data write is visible to the next instruction
+//! fetch for free. AArch64 does not make that guarantee: after copying kernel/PE bytes into
+//! freshly allocated pages, the data cache and instruction cache have to be explicitly
+//! synchronized before control is transferred into that memory, or the core may execute stale
+//! (or no) instructions from the I-cache.
+
+/// Makes the data written to `region` visible to instruction fetches, so it is safe to jump into.
+///
+/// On x86_64 this is a no-op. On AArch64 it cleans each cache line of `region` to the point of
+/// unification and invalidates the instruction cache.
+#[cfg(target_arch = "aarch64")]
+pub(crate) fn make_instruction_cache_coherent(region: &[u8]) {
+    const CACHE_LINE_SIZE: usize = 16;
+
+    let start = region.as_ptr() as usize;
+    let end = start + region.len();
+    let first_line = start & !(CACHE_LINE_SIZE - 1);
+
+    let mut addr = first_line;
+    while addr < end {
+        // SAFETY: `addr` lies within `region`'s allocation (rounded down to a cache line), and
+        // `dc cvau` only cleans the data cache; it cannot fault on mapped, writable memory.
+        unsafe {
+            core::arch::asm!("dc cvau, {0}", in(reg) addr);
+        }
+        addr += CACHE_LINE_SIZE;
+    }
+
+    // SAFETY: `dsb ish` is a barrier with no memory-safety preconditions; it ensures the cache
+    // maintenance above is visible to all observers before we invalidate the I-cache below.
+    unsafe {
+        core::arch::asm!("dsb ish");
+    }
+
+    // SAFETY: `ic iallu` invalidates the entire instruction cache to point of unification; the
+    // following `dsb ish` guarantees that invalidation has completed before `isb` flushes the
+    // pipeline, so no stale instructions can be fetched from the jump target.
+    unsafe {
+        core::arch::asm!("ic iallu");
+        core::arch::asm!("dsb ish");
+        core::arch::asm!("isb");
+    }
+}
+
+/// On x86_64 the instruction cache is coherent with the data cache, so there is nothing to do.
+#[cfg(not(target_arch = "aarch64"))]
+pub(crate) fn make_instruction_cache_coherent(_region: &[u8]) {}
@@ -0,0 +1,155 @@
+//! Per-host boot configuration, fetched over the network before the kernel and initrd, in the
+//! pxelinux tradition of keying config lookup by the client's MAC address with a generic
+//! fallback. Replaces the single hardcoded kernel command line with `kernel=`, `initrd=`, and
+//! `append=`/`cmdline=` directives parsed out of that file.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use log::info;
+use uefi::proto::network::pxe::{BaseCode, DhcpV4Packet};
+use uefi::{CStr8, ScopedProtocol, Status};
+
+use crate::verify::Manifest;
+use crate::{download_file, owned_cstr8, AppResult, Transport};
+
+const DEFAULT_KERNEL_FILENAME: &str = "bzImage";
+const DEFAULT_INITRD_FILENAME: &str = "initrd";
+const MAX_CONFIG_SIZE_BYTES: u64 = 16 << 10;
+
+/// The boot configuration driving the rest of `run()`: which files to pull and what load
+/// options to pass to the kernel.
+pub(crate) struct BootConfig {
+    pub(crate) kernel_filename: String,
+    pub(crate) initrd_filename: String,
+    /// Owned, null-terminated UCS-2 load options, ready for `set_load_options`.
+    pub(crate) cmdline: Vec<u16>,
+}
+
+impl Default for BootConfig {
+    fn default() -> Self {
+        Self {
+            kernel_filename: DEFAULT_KERNEL_FILENAME.into(),
+            initrd_filename: DEFAULT_INITRD_FILENAME.into(),
+            cmdline: to_ucs2(""),
+        }
+    }
+}
+
+/// Fetches the first config file that exists among the per-host candidates, verifies it against
+/// `manifest` the same way the kernel and initrd are verified, and parses it into a
+/// [`BootConfig`]. Falls back to the built-in defaults if none of the candidates are found.
+///
+/// The config file carries `cmdline`, which is handed to the kernel unchecked by anything past
+/// this point, so it has to be covered by the same signed-manifest trust anchor as the kernel and
+/// initrd: an unauthenticated config file would let a network attacker inject boot parameters even
+/// though they can't forge the kernel/initrd bytes themselves.
+pub(crate) fn fetch(
+    transport: Transport,
+    bc: &mut ScopedProtocol<BaseCode>,
+    server_ip: &core::net::IpAddr,
+    use_ipv6: bool,
+    manifest: &Manifest,
+) -> AppResult<BootConfig> {
+    // The client-MAC-keyed lookup is only meaningful over IPv4: DHCPv6 doesn't carry the link
+    // layer address in the acknowledgement the way BOOTP's `chaddr` does, so IPv6 clients only
+    // ever see the generic fallback.
+    let candidates = if use_ipv6 {
+        vec![String::from("default.conf")]
+    } else {
+        let packet: &DhcpV4Packet = bc.mode().dhcp_ack().as_ref();
+        candidate_filenames(packet)
+    };
+
+    for name in candidates {
+        let cstr_storage = owned_cstr8(&name);
+        let filename = CStr8::from_bytes_with_nul(&cstr_storage).map_err(|_| Status::INVALID_PARAMETER)?;
+
+        match download_file(transport, bc, server_ip, filename, MAX_CONFIG_SIZE_BYTES) {
+            Ok(data) => {
+                info!("Using boot config {name}");
+                crate::verify::verify(&name, &data, manifest.digest_for(filename)?)?;
+                return parse(&data);
+            }
+            Err(Status::NOT_FOUND) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    info!("No boot config found, using built-in defaults");
+    Ok(BootConfig::default())
+}
+
+/// Candidate config filenames to try, most to least specific: first the client's own MAC
+/// address, then a generic fallback shared by every host.
+fn candidate_filenames(packet: &DhcpV4Packet) -> Vec<String> {
+    let mut names = Vec::new();
+    if let Some(mac) = client_mac(packet) {
+        names.push(format!(
+            "01-{:02x}-{:02x}-{:02x}-{:02x}-{:02x}-{:02x}.conf",
+            mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]
+        ));
+    }
+    names.push(String::from("default.conf"));
+    names
+}
+
+fn client_mac(packet: &DhcpV4Packet) -> Option<[u8; 6]> {
+    if packet.bootp_hw_addr_len != 6 {
+        return None;
+    }
+    let mut mac = [0u8; 6];
+    mac.copy_from_slice(&packet.bootp_hw_addr[..6]);
+    Some(mac)
+}
+
+fn parse(data: &[u8]) -> AppResult<BootConfig> {
+    let mut config = BootConfig::default();
+    let mut cmdline = String::new();
+
+    for line in data.split(|&b| b == b'\n') {
+        let line = trim(line);
+        if line.is_empty() || line[0] == b'#' {
+            continue;
+        }
+
+        let Some(eq) = line.iter().position(|&b| b == b'=') else {
+            continue;
+        };
+        let key = trim(&line[..eq]);
+        let value = trim(&line[eq + 1..]);
+        let Ok(value) = core::str::from_utf8(value) else {
+            continue;
+        };
+
+        match key {
+            b"kernel" => config.kernel_filename = value.into(),
+            b"initrd" => config.initrd_filename = value.into(),
+            b"append" | b"cmdline" => cmdline = value.into(),
+            _ => info!("Ignoring unknown boot config directive: {key:?}"),
+        }
+    }
+
+    config.cmdline = to_ucs2(&cmdline);
+    Ok(config)
+}
+
+fn trim(mut s: &[u8]) -> &[u8] {
+    while let [b' ' | b'\t' | b'\r', rest @ ..] = s {
+        s = rest;
+    }
+    while let [rest @ .., b' ' | b'\t' | b'\r'] = s {
+        s = rest;
+    }
+    s
+}
+
+/// Converts an ASCII command line into an owned, null-terminated UCS-2 buffer for
+/// `set_load_options`.
+fn to_ucs2(s: &str) -> Vec<u16> {
+    let mut buf: Vec<u16> = s.chars().map(|c| c as u16).collect();
+    buf.push(0);
+    buf
+}
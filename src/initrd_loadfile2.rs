@@ -0,0 +1,132 @@
+//! Exposes the initrd through `EFI_LOAD_FILE2_PROTOCOL`, for EFI stubs that locate their initrd
+//! via the vendor-media device path protocol rather than the `LINUX_EFI_INITRD_MEDIA_GUID`
+//! configuration table installed by [`crate::install_initrd_config_table`]. Installing both
+//! broadens compatibility with older and non-Linux EFI stubs.
+
+use core::ffi::c_void;
+use core::ptr::NonNull;
+
+use log::info;
+use uefi::boot::*;
+use uefi::proto::device_path::build::{self, DevicePathBuilder};
+use uefi::proto::device_path::DevicePath;
+use uefi::{guid, Guid, Handle, Status};
+
+use crate::AppResult;
+use crate::LINUX_EFI_INITRD_MEDIA_GUID;
+
+const LOAD_FILE2_PROTOCOL_GUID: Guid = guid!("4006c0c1-fcb3-403e-996d-4a6c8724e06d");
+
+/// The initrd currently installed behind the `LoadFile2` instance, read back by `load_file` on
+/// every invocation. Set once from `install` before the protocol is published, so there is no
+/// concurrent access to race against in this single-threaded boot environment.
+static mut INITRD: Option<(NonNull<u8>, usize)> = None;
+
+#[repr(C)]
+struct LoadFile2Protocol {
+    load_file: unsafe extern "efiapi" fn(
+        this: *const LoadFile2Protocol,
+        file_path: *const c_void,
+        boot_policy: bool,
+        buffer_size: *mut usize,
+        buffer: *mut c_void,
+    ) -> Status,
+}
+
+static LOAD_FILE2_PROTOCOL: LoadFile2Protocol = LoadFile2Protocol { load_file };
+
+/// Installs a `LoadFile2` protocol instance, on a handle carrying a device path ending in the
+/// `LINUX_EFI_INITRD_MEDIA_GUID` vendor-media node, that serves `initrd` out of the already
+/// allocated buffer at `(initrd_base, initrd_size)`.
+pub(crate) fn install(initrd_base: NonNull<u8>, initrd_size: usize) -> AppResult<Handle> {
+    // SAFETY: nothing else touches `INITRD` before the protocol is installed below, and nothing
+    // reads it before then either.
+    unsafe {
+        INITRD = Some((initrd_base, initrd_size));
+    }
+
+    // Leaked so the device path stays valid for as long as the handle it's installed on.
+    let storage: &'static mut alloc::vec::Vec<u8> = alloc::boxed::Box::leak(alloc::boxed::Box::new(alloc::vec::Vec::new()));
+    let mut builder = DevicePathBuilder::with_vec(storage);
+    builder = builder
+        .push(&build::media::Vendor {
+            vendor_guid: LINUX_EFI_INITRD_MEDIA_GUID,
+            vendor_defined_data: &[],
+        })
+        .map_err(|e| {
+            info!("Failed to build initrd device path: {:?}", e);
+            Status::OUT_OF_RESOURCES
+        })?;
+    let device_path = builder.finalize().map_err(|e| {
+        info!("Failed to finalize initrd device path: {:?}", e);
+        Status::OUT_OF_RESOURCES
+    })?;
+
+    // SAFETY: `device_path` and `LOAD_FILE2_PROTOCOL` both outlive the boot services they are
+    // registered with, and the interface pointer matches `EFI_LOAD_FILE2_PROTOCOL`'s layout.
+    let handle = unsafe {
+        install_protocol_interface(
+            None,
+            &LOAD_FILE2_PROTOCOL_GUID,
+            &LOAD_FILE2_PROTOCOL as *const LoadFile2Protocol as *const c_void,
+        )
+    }
+    .map_err(|e| {
+        info!("Failed to install LoadFile2 protocol: {:?}", e);
+        e.status()
+    })?;
+
+    // SAFETY: `device_path` outlives the handle it's installed on.
+    unsafe {
+        install_protocol_interface(
+            Some(handle),
+            &DevicePath::GUID,
+            device_path.as_ffi_ptr() as *const c_void,
+        )
+    }
+    .map_err(|e| {
+        info!("Failed to install initrd device path: {:?}", e);
+        e.status()
+    })?;
+
+    info!("Installed initrd LoadFile2 protocol on {handle:?}");
+    Ok(handle)
+}
+
+/// `EFI_LOAD_FILE2` callback: returns `BUFFER_TOO_SMALL` with the required size when called with
+/// no buffer or one too small, otherwise copies the cached initrd into the caller's buffer.
+unsafe extern "efiapi" fn load_file(
+    _this: *const LoadFile2Protocol,
+    _file_path: *const c_void,
+    _boot_policy: bool,
+    buffer_size: *mut usize,
+    buffer: *mut c_void,
+) -> Status {
+    // SAFETY: `install` always populates `INITRD` before this protocol is reachable.
+    let Some((initrd_base, initrd_size)) = (unsafe { INITRD }) else {
+        return Status::NOT_FOUND;
+    };
+
+    if buffer_size.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+
+    // SAFETY: the firmware passes a valid pointer to an in/out size per the spec.
+    let requested_size = unsafe { *buffer_size };
+    // SAFETY: see above.
+    unsafe {
+        *buffer_size = initrd_size;
+    }
+
+    if buffer.is_null() || requested_size < initrd_size {
+        return Status::BUFFER_TOO_SMALL;
+    }
+
+    // SAFETY: the firmware guarantees `buffer` is valid for `requested_size >= initrd_size`
+    // bytes when non-null, and `initrd_base` points to `initrd_size` readable bytes per `install`.
+    unsafe {
+        core::ptr::copy_nonoverlapping(initrd_base.as_ptr(), buffer as *mut u8, initrd_size);
+    }
+
+    Status::SUCCESS
+}
@@ -1,5 +1,5 @@
-#![no_main]
-#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(not(test), no_std)]
 
 extern crate alloc;
 
@@ -15,10 +15,21 @@ use uefi::allocator::Allocator;
 use uefi::boot::*;
 use uefi::prelude::*;
 use uefi::proto::loaded_image::LoadedImage;
-use uefi::proto::network::pxe::{BaseCode, DhcpV4Packet};
-use uefi::{CStr16, Guid, guid};
+use uefi::proto::network::http::{HttpHelper, HttpMethod};
+use uefi::proto::network::pxe::{BaseCode, DhcpV4Packet, DhcpV6Packet};
+use uefi::{Guid, guid};
 
-const LINUX_EFI_INITRD_MEDIA_GUID: Guid = guid!("5568e427-68fc-4f3d-ac74-ca555231cc68");
+mod arch;
+mod config;
+mod initrd_loadfile2;
+mod pe_loader;
+mod verify;
+
+pub(crate) const LINUX_EFI_INITRD_MEDIA_GUID: Guid = guid!("5568e427-68fc-4f3d-ac74-ca555231cc68");
+
+/// Preferred address family, used when the provisioning network offers both. If IPv6 PXE isn't
+/// available we fall back to IPv4 regardless.
+const PREFER_IPV6: bool = false;
 
 #[global_allocator]
 static GLOBAL_ALLOCATOR: Allocator = Allocator;
@@ -39,26 +50,63 @@ fn main() -> Status {
 
 fn run() -> AppResult<Status> {
     let mut bc = find_pxebc_proto()?;
-    start_pxe_if_needed(&mut bc)?;
+    let use_ipv6 = start_pxe_if_needed(&mut bc)?;
 
-    perform_dhcp(&mut bc)?;
-    let (ip_addr, server_ip) = get_network_config(&bc);
+    perform_dhcp(&mut bc, use_ipv6)?;
+    let (ip_addr, server_ip) = get_network_config(&bc, use_ipv6)?;
     info!("I have IP address: {ip_addr}");
 
-    let kernel_data = download_file(&mut bc, &server_ip, cstr8!("bzImage"), 32 << 20)?;
-    let initrd_data = download_file(&mut bc, &server_ip, cstr8!("initrd"), 1024 << 20)?;
+    let transport = detect_transport(&bc, use_ipv6);
+    info!("Selected transport: {transport:?}");
+
+    // Fetched and authenticated before the boot config, since the config's `cmdline` is handed to
+    // the kernel unchecked and so needs the same signed-manifest trust anchor as the kernel and
+    // initrd, not just the files it names.
+    let manifest_data = download_file(transport, &mut bc, &server_ip, cstr8!("manifest.sha256"), 4096)?;
+    let manifest = verify::Manifest::parse(&manifest_data)?;
+
+    let boot_config = config::fetch(transport, &mut bc, &server_ip, use_ipv6, &manifest)?;
+
+    let kernel_storage = owned_cstr8(&boot_config.kernel_filename);
+    let kernel_filename = uefi::CStr8::from_bytes_with_nul(&kernel_storage).map_err(|_| Status::INVALID_PARAMETER)?;
+    let initrd_storage = owned_cstr8(&boot_config.initrd_filename);
+    let initrd_filename = uefi::CStr8::from_bytes_with_nul(&initrd_storage).map_err(|_| Status::INVALID_PARAMETER)?;
+
+    let kernel_data = download_file(transport, &mut bc, &server_ip, kernel_filename, 32 << 20)?;
+    let initrd_data = download_file(transport, &mut bc, &server_ip, initrd_filename, 1024 << 20)?;
+
+    // The initrd must be verified before it is exposed to the kernel via the config table, and
+    // the kernel before it is handed to the image loader: an unauthenticated TFTP/HTTP transfer
+    // is not a trust boundary the rest of the boot chain should have to account for.
+    verify::verify("initrd", &initrd_data, manifest.digest_for(initrd_filename)?)?;
+    verify::verify("kernel", &kernel_data, manifest.digest_for(kernel_filename)?)?;
 
     let initrd_base = alloc_pages_and_copy(&initrd_data)?;
     // SAFETY: initrd_base is valid pointer when function succeeds
     unsafe {
         install_initrd_config_table(initrd_base, initrd_data.len())?;
     }
+    // Also expose the initrd via LoadFile2 for EFI stubs that don't look at the config table.
+    // This is a best-effort compatibility addition alongside the config table above, so a
+    // firmware quirk that makes it fail (e.g. a handle/GUID clash) shouldn't abort a boot that
+    // would otherwise succeed via the config table path.
+    if let Err(e) = initrd_loadfile2::install(initrd_base, initrd_data.len()) {
+        info!("Failed to install LoadFile2 protocol for initrd, continuing without it: {:?}", e);
+    }
 
-    load_and_start_kernel_from_pages(&kernel_data)?;
+    load_and_start_kernel_from_pages(&kernel_data, &boot_config.cmdline)?;
 
     Ok(Status::SUCCESS)
 }
 
+/// Builds a null-terminated byte buffer suitable for `uefi::CStr8::from_bytes_with_nul`.
+pub(crate) fn owned_cstr8(s: &str) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(s.len() + 1);
+    buf.extend_from_slice(s.as_bytes());
+    buf.push(0);
+    buf
+}
+
 fn find_pxebc_proto() -> AppResult<ScopedProtocol<BaseCode>> {
     let handle_buffer = locate_handle_buffer(SearchType::from_proto::<BaseCode>()).map_err(|e| {
         match e.status() {
@@ -78,39 +126,170 @@ fn find_pxebc_proto() -> AppResult<ScopedProtocol<BaseCode>> {
     Err(Status::NOT_FOUND)
 }
 
-fn start_pxe_if_needed(bc: &mut ScopedProtocol<BaseCode>) -> AppResult<()> {
+/// Starts the PXE Base Code protocol, preferring IPv6 when [`PREFER_IPV6`] asks for it and the
+/// firmware supports it, and returns which address family ended up selected.
+fn start_pxe_if_needed(bc: &mut ScopedProtocol<BaseCode>) -> AppResult<bool> {
     info!("Opened PXE Base Code protocol");
-    if !bc.mode().started() {
-        // TODO: ipv6 support
-        info!("Starting...");
-        bc.start(false).map_err(|e| {
-            info!("Failed to start PXE: {:?}", e);
-            e.status()
-        })?;
+    if bc.mode().started() {
+        return Ok(bc.mode().using_ipv6());
     }
-    Ok(())
+
+    if PREFER_IPV6 {
+        info!("Starting... (IPv6)");
+        match bc.start(true) {
+            Ok(()) => return Ok(true),
+            Err(e) => info!("Failed to start PXE over IPv6, falling back to IPv4: {:?}", e),
+        }
+    }
+
+    info!("Starting... (IPv4)");
+    bc.start(false).map_err(|e| {
+        info!("Failed to start PXE: {:?}", e);
+        e.status()
+    })?;
+    Ok(false)
 }
 
-fn perform_dhcp(bc: &mut ScopedProtocol<BaseCode>) -> AppResult<()> {
+fn perform_dhcp(bc: &mut ScopedProtocol<BaseCode>, use_ipv6: bool) -> AppResult<()> {
     if bc.mode().dhcp_ack_received() {
         info!("DHCP already set up... skipping DHCP process");
         return Ok(());
     }
     info!("Trying DHCP...");
-    bc.dhcp(false).map_err(|e| {
+    bc.dhcp(use_ipv6).map_err(|e| {
         info!("Failed DHCP: {:?}", e);
         e.status()
     })
 }
 
-fn get_network_config(bc: &ScopedProtocol<BaseCode>) -> (net::IpAddr, net::IpAddr) {
+fn get_network_config(bc: &ScopedProtocol<BaseCode>, use_ipv6: bool) -> AppResult<(net::IpAddr, net::IpAddr)> {
+    if use_ipv6 {
+        let packet: &DhcpV6Packet = bc.mode().dhcpv6_ack().as_ref();
+        let ip_addr = parse_dhcpv6_client_address(&packet.dhcp_options).ok_or(Status::NOT_FOUND)?;
+        let server_ip = parse_dhcpv6_bootfile_server(&packet.dhcp_options).ok_or(Status::NOT_FOUND)?;
+        return Ok((net::IpAddr::V6(ip_addr), net::IpAddr::V6(server_ip)));
+    }
+
     let packet: &DhcpV4Packet = bc.mode().dhcp_ack().as_ref();
     let ip_addr = net::IpAddr::from(packet.bootp_yi_addr);
     let server_ip = net::IpAddr::from(packet.bootp_si_addr);
-    (ip_addr, server_ip)
+    Ok((ip_addr, server_ip))
+}
+
+/// Walks a DHCPv6 option buffer (2-byte big-endian code, 2-byte big-endian length, then data) and
+/// returns the data of the first option matching `code`.
+fn dhcpv6_option(options: &[u8], code: u16) -> Option<&[u8]> {
+    let mut options = options;
+    while options.len() >= 4 {
+        let opt_code = u16::from_be_bytes([options[0], options[1]]);
+        let opt_len = u16::from_be_bytes([options[2], options[3]]) as usize;
+        let data = options.get(4..4 + opt_len)?;
+        if opt_code == code {
+            return Some(data);
+        }
+        options = &options[4 + opt_len..];
+    }
+    None
+}
+
+/// DHCPv6 option 3 (Identity Association for Non-temporary Addresses) and its nested option 5
+/// (IA Address), per RFC 8415 §21.4/21.6.
+const DHCPV6_OPTION_IA_NA: u16 = 3;
+const DHCPV6_OPTION_IAADDR: u16 = 5;
+/// DHCPv6 option 59 (Boot File URL), per RFC 5970 §3.1.
+const DHCPV6_OPTION_BOOTFILE_URL: u16 = 59;
+
+fn parse_dhcpv6_client_address(options: &[u8]) -> Option<net::Ipv6Addr> {
+    let ia_na = dhcpv6_option(options, DHCPV6_OPTION_IA_NA)?;
+    // IA_NA's fixed fields (IAID, T1, T2) take up the first 12 bytes; nested options follow.
+    let inner_options = ia_na.get(12..)?;
+    let iaaddr = dhcpv6_option(inner_options, DHCPV6_OPTION_IAADDR)?;
+    let addr_bytes: [u8; 16] = iaaddr.get(..16)?.try_into().ok()?;
+    Some(net::Ipv6Addr::from(addr_bytes))
+}
+
+/// Extracts the server address from the `[...]` host portion of a `tftp://` or `http(s)://` Boot
+/// File URL, since DHCPv6 replies don't carry a bare "server identifier" address the way BOOTP's
+/// `siaddr` does.
+fn parse_dhcpv6_bootfile_server(options: &[u8]) -> Option<net::Ipv6Addr> {
+    let url = bootfile_url(options)?;
+    let start = url.find('[')? + 1;
+    let end = start + url[start..].find(']')?;
+    url[start..end].parse().ok()
+}
+
+fn bootfile_url(options: &[u8]) -> Option<&str> {
+    core::str::from_utf8(dhcpv6_option(options, DHCPV6_OPTION_BOOTFILE_URL)?).ok()
+}
+
+/// Boot-time transport used to fetch the kernel and initrd from the provisioning server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Transport {
+    Tftp,
+    Http,
+}
+
+/// DHCP option 60 (vendor class identifier), used by UEFI HTTP Boot to advertise `HTTPClient`.
+const DHCP_OPTION_VENDOR_CLASS: u8 = 60;
+const DHCP_OPTION_END: u8 = 255;
+const DHCP_OPTION_PAD: u8 = 0;
+const HTTP_CLIENT_VENDOR_CLASS: &[u8] = b"HTTPClient";
+
+/// Picks HTTP when the DHCP offer identifies this as an HTTP Boot, falling back to the TFTP path
+/// used by legacy PXE ROMs otherwise. Over IPv4 that's signalled by the vendor class identifier;
+/// over IPv6 the Boot File URL's own scheme already tells us.
+fn detect_transport(bc: &ScopedProtocol<BaseCode>, use_ipv6: bool) -> Transport {
+    if use_ipv6 {
+        let packet: &DhcpV6Packet = bc.mode().dhcpv6_ack().as_ref();
+        let is_http = bootfile_url(&packet.dhcp_options)
+            .is_some_and(|url| url.starts_with("http://") || url.starts_with("https://"));
+        return if is_http { Transport::Http } else { Transport::Tftp };
+    }
+
+    let packet: &DhcpV4Packet = bc.mode().dhcp_ack().as_ref();
+    if dhcp_options_contain_http_client(&packet.dhcp_options) {
+        Transport::Http
+    } else {
+        Transport::Tftp
+    }
 }
 
-fn download_file(
+fn dhcp_options_contain_http_client(mut options: &[u8]) -> bool {
+    while let Some((&code, rest)) = options.split_first() {
+        match code {
+            DHCP_OPTION_END => break,
+            DHCP_OPTION_PAD => options = rest,
+            _ => {
+                let Some((&len, rest)) = rest.split_first() else {
+                    break;
+                };
+                let Some((value, rest)) = rest.split_at_checked(len as usize) else {
+                    break;
+                };
+                if code == DHCP_OPTION_VENDOR_CLASS && value.starts_with(HTTP_CLIENT_VENDOR_CLASS) {
+                    return true;
+                }
+                options = rest;
+            }
+        }
+    }
+    false
+}
+
+pub(crate) fn download_file(
+    transport: Transport,
+    bc: &mut ScopedProtocol<BaseCode>,
+    server_ip: &net::IpAddr,
+    filename: &uefi::CStr8,
+    max_size_bytes: u64,
+) -> AppResult<Vec<u8>> {
+    match transport {
+        Transport::Tftp => download_file_tftp(bc, server_ip, filename, max_size_bytes),
+        Transport::Http => download_file_http(server_ip, filename, max_size_bytes),
+    }
+}
+
+fn download_file_tftp(
     bc: &mut ScopedProtocol<BaseCode>,
     server_ip: &net::IpAddr,
     filename: &uefi::CStr8,
@@ -138,6 +317,71 @@ fn download_file(
     Ok(buf)
 }
 
+/// Fetches `http://<server_ip>/<filename>` using the UEFI HTTP protocol. This is considerably
+/// faster than TFTP's lockstep 512-byte windows for large payloads like the initrd.
+fn download_file_http(server_ip: &net::IpAddr, filename: &uefi::CStr8, max_size_bytes: u64) -> AppResult<Vec<u8>> {
+    // `IpAddr`'s `Display` impl doesn't bracket IPv6 literals, and an unbracketed `2001:db8::1` in
+    // an authority is ambiguous with a port separator, so bracket it ourselves.
+    let url = if server_ip.is_ipv6() {
+        alloc::format!("http://[{server_ip}]/{filename}")
+    } else {
+        alloc::format!("http://{server_ip}/{filename}")
+    };
+    info!("Fetching {url} via HTTP");
+
+    let mut http = HttpHelper::new(*server_ip, server_ip.is_ipv6()).map_err(|e| {
+        info!("Failed to set up HTTP child handle: {:?}", e);
+        e.status()
+    })?;
+
+    http.configure().map_err(|e| {
+        info!("Failed to configure HTTP: {:?}", e);
+        e.status()
+    })?;
+
+    http.request(HttpMethod::GET, &url, None).map_err(|e| {
+        info!("HTTP GET {url} failed: {:?}", e);
+        e.status()
+    })?;
+
+    let status_code = http.response_status_code();
+    if !(200..300).contains(&status_code) {
+        info!("HTTP GET {url} returned status {status_code}");
+        return Err(if status_code == 404 {
+            Status::NOT_FOUND
+        } else {
+            Status::PROTOCOL_ERROR
+        });
+    }
+
+    let content_length = http.response_content_length();
+    if let Some(size) = content_length {
+        if size > max_size_bytes {
+            info!("File size too large for {filename}");
+            return Err(Status::ABORTED);
+        }
+    }
+
+    let mut buf = Vec::with_capacity(content_length.unwrap_or(0) as usize);
+    loop {
+        let chunk = http.read_response_chunk().map_err(|e| {
+            info!("Failed to read HTTP response body for {filename}: {:?}", e);
+            e.status()
+        })?;
+        let Some(chunk) = chunk else {
+            break;
+        };
+        if buf.len() as u64 + chunk.len() as u64 > max_size_bytes {
+            info!("File size too large for {filename}");
+            return Err(Status::ABORTED);
+        }
+        buf.extend_from_slice(&chunk);
+    }
+
+    info!("{filename} size: {}", buf.len());
+    Ok(buf)
+}
+
 fn alloc_pages_and_copy(data: &[u8]) -> AppResult<NonNull<u8>> {
     let pages = data.len().div_ceil(PAGE_SIZE);
     let addr = allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, pages).map_err(|e| {
@@ -186,36 +430,33 @@ unsafe fn install_initrd_config_table(base: NonNull<u8>, size: usize) -> AppResu
     }
 }
 
-fn load_and_start_kernel_from_pages(kernel_data: &[u8]) -> AppResult<()> {
-    let kernel_base = alloc_pages_and_copy(kernel_data)?;
-    let kernel_len = kernel_data.len();
-
-    // SAFETY: we copied kernel_len bytes into kernel_base
-    let buffer = unsafe { core::slice::from_raw_parts(kernel_base.as_ptr(), kernel_len) };
-
-    let source = LoadImageSource::FromBuffer {
-        buffer,
-        file_path: None,
-    };
-
-    let kernel_handle = load_image(image_handle(), source).map_err(|e| {
-        info!("Failed to load kernel image: {:?}", e);
-        e.status()
-    })?;
+fn load_and_start_kernel_from_pages(kernel_data: &[u8], cmdline: &[u16]) -> AppResult<()> {
+    // We parse and relocate the PE ourselves rather than calling `load_image`/`start_image`, so
+    // that kernels the firmware refuses to load (unsigned, or lacking a conforming EFI stub) can
+    // still be chainloaded.
+    let image = pe_loader::Image::load(kernel_data)?;
 
-    setup_kernel_options(kernel_handle)?;
+    // TODO: we don't install a LoadedImage protocol instance for the kernel the way
+    // `load_image` would, so `setup_kernel_options` below sets load options on our own image
+    // handle rather than a dedicated one for the kernel. An EFI stub that reads its cmdline via
+    // LoadedImage on the handle passed to its entry point won't see it until we install one.
+    let kernel_handle = image_handle();
+    setup_kernel_options(kernel_handle, cmdline)?;
 
     info!("Starting kernel image");
 
-    start_image(kernel_handle).map_err(|e| {
-        info!("Failed to start image: {:?}", e);
-        e.status()
-    })?;
+    let status = image.start(kernel_handle);
+    if status.is_error() {
+        info!("Kernel entry point returned error: {:?}", status);
+        return Err(status);
+    }
 
     Ok(())
 }
 
-fn setup_kernel_options(kernel_handle: Handle) -> AppResult<()> {
+/// Sets the kernel's load options from `cmdline`, an owned null-terminated UCS-2 buffer produced
+/// by [`config::fetch`] from the network boot configuration.
+fn setup_kernel_options(kernel_handle: Handle, cmdline: &[u16]) -> AppResult<()> {
     let mut image = open_protocol_exclusive::<LoadedImage>(kernel_handle).map_err(|e| {
         info!("Failed to open LoadedImage protocol: {:?}", e);
         e.status()
@@ -223,15 +464,12 @@ fn setup_kernel_options(kernel_handle: Handle) -> AppResult<()> {
 
     info!("Setting kernel load options");
 
-    // TODO: This works because the string will not get dropped. When we start allowing users to
-    // specify their own options, we should probably take a reference annotated with lifetimes.
-    static KERNEL_OPTS: &CStr16 = cstr16!(
-        "init=/nix/store/pg9asbr6hx4515is7akx9ypygg28ama9-nixos-system-nixos-kexec-25.05.20251019.33c6dca/init loglevel=4 efi=debug"
-    );
-
-    // SAFETY: `KERNEL_OPTS` has static lifetime.
+    let options_bytes = (cmdline.len() * core::mem::size_of::<u16>()) as u32;
+    // SAFETY: `cmdline` is a valid, null-terminated UCS-2 buffer. `set_load_options` stores the
+    // pointer rather than copying the data; the caller keeps `cmdline` alive until the kernel's
+    // entry point returns.
     unsafe {
-        image.set_load_options(KERNEL_OPTS.as_bytes().as_ptr(), KERNEL_OPTS.num_bytes() as u32);
+        image.set_load_options(cmdline.as_ptr() as *const u8, options_bytes);
     }
 
     Ok(())
@@ -241,3 +479,77 @@ fn error_exit(status: Status) -> Status {
     boot::stall(Duration::from_secs(10));
     status
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dhcpv6_option_finds_matching_code() {
+        // code 3, len 2, data [0xaa, 0xbb], followed by code 59, len 1, data [0x01].
+        let options = [0x00, 0x03, 0x00, 0x02, 0xaa, 0xbb, 0x00, 0x3b, 0x00, 0x01, 0x01];
+        assert_eq!(dhcpv6_option(&options, 3), Some(&options[4..6]));
+        assert_eq!(dhcpv6_option(&options, 59), Some(&options[10..11]));
+        assert_eq!(dhcpv6_option(&options, 99), None);
+    }
+
+    #[test]
+    fn dhcpv6_option_rejects_truncated_input() {
+        assert_eq!(dhcpv6_option(&[], 3), None);
+        // Header present but shorter than a full 4-byte option header.
+        assert_eq!(dhcpv6_option(&[0x00, 0x03, 0x00], 3), None);
+        // Header claims more data than is actually present.
+        assert_eq!(dhcpv6_option(&[0x00, 0x03, 0x00, 0x05, 0xaa], 3), None);
+    }
+
+    #[test]
+    fn dhcpv6_option_skips_options_it_does_not_want() {
+        // code 1 (skipped), len 4, then code 3, len 0.
+        let options = [0x00, 0x01, 0x00, 0x04, 0, 0, 0, 0, 0x00, 0x03, 0x00, 0x00];
+        assert_eq!(dhcpv6_option(&options, 3), Some(&options[12..12]));
+    }
+
+    #[test]
+    fn parse_dhcpv6_client_address_extracts_nested_iaaddr() {
+        let addr = net::Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+        let mut iaaddr_option = alloc::vec![0x00, 0x05, 0x00, 0x10];
+        iaaddr_option.extend_from_slice(&addr.octets());
+
+        let mut ia_na_body = alloc::vec![0u8; 12]; // IAID, T1, T2
+        ia_na_body.extend_from_slice(&iaaddr_option);
+
+        let mut options = alloc::vec![0x00, 0x03];
+        options.extend_from_slice(&(ia_na_body.len() as u16).to_be_bytes());
+        options.extend_from_slice(&ia_na_body);
+
+        assert_eq!(parse_dhcpv6_client_address(&options), Some(addr));
+    }
+
+    #[test]
+    fn parse_dhcpv6_client_address_rejects_missing_ia_na() {
+        assert_eq!(parse_dhcpv6_client_address(&[]), None);
+    }
+
+    #[test]
+    fn parse_dhcpv6_bootfile_server_extracts_bracketed_host() {
+        let url = b"tftp://[2001:db8::1]/bzImage";
+        let mut options = alloc::vec![0x00, 0x3b];
+        options.extend_from_slice(&(url.len() as u16).to_be_bytes());
+        options.extend_from_slice(url);
+
+        assert_eq!(
+            parse_dhcpv6_bootfile_server(&options),
+            Some(net::Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1))
+        );
+    }
+
+    #[test]
+    fn parse_dhcpv6_bootfile_server_rejects_unbracketed_url() {
+        let url = b"tftp://2001:db8::1/bzImage";
+        let mut options = alloc::vec![0x00, 0x3b];
+        options.extend_from_slice(&(url.len() as u16).to_be_bytes());
+        options.extend_from_slice(url);
+
+        assert_eq!(parse_dhcpv6_bootfile_server(&options), None);
+    }
+}
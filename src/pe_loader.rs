@@ -0,0 +1,299 @@
+//! An in-house PE/COFF loader, used in place of the firmware's `load_image`/`start_image` so
+//! kernels UEFI itself refuses to load (unsigned, or lacking a conforming EFI stub) can still be
+//! chainloaded. This mirrors, in miniature, what the firmware's own PE loader does: map sections
+//! to their virtual addresses, apply base relocations for the address the image actually landed
+//! at, then jump to the entry point.
+
+use core::mem::transmute;
+use core::ptr::NonNull;
+
+use goblin::pe::PE;
+use log::info;
+use uefi::boot::*;
+use uefi::prelude::*;
+
+use crate::arch::make_instruction_cache_coherent;
+use crate::AppResult;
+
+type EfiImageEntry = unsafe extern "efiapi" fn(Handle, SystemTable<Boot>) -> Status;
+
+/// Relocation directory entry types we know how to apply. Everything else is rejected: silently
+/// skipping an unknown type would leave the image half-relocated.
+const IMAGE_REL_BASED_ABSOLUTE: u16 = 0;
+const IMAGE_REL_BASED_HIGHLOW: u16 = 3;
+const IMAGE_REL_BASED_DIR64: u16 = 10;
+
+/// A kernel image loaded into freshly allocated pages and relocated to its actual base address.
+pub(crate) struct Image {
+    base: NonNull<u8>,
+    entry_point: usize,
+}
+
+impl Image {
+    /// Parses `kernel_data` as a PE image, maps its sections into `LOADER_CODE` pages, and
+    /// applies base relocations for wherever those pages ended up.
+    pub(crate) fn load(kernel_data: &[u8]) -> AppResult<Self> {
+        let pe = PE::parse(kernel_data).map_err(|e| {
+            info!("Failed to parse PE image: {e}");
+            Status::LOAD_ERROR
+        })?;
+
+        let image_base = pe.image_base;
+        let entry_rva = pe.entry;
+
+        let size_of_image = pe
+            .sections
+            .iter()
+            .map(|s| s.virtual_address as usize + s.virtual_size as usize)
+            .max()
+            .ok_or(Status::LOAD_ERROR)?;
+
+        let pages = size_of_image.div_ceil(PAGE_SIZE);
+        let base = allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_CODE, pages).map_err(|e| {
+            info!("Failed to allocate pages for kernel image: {:?}", e);
+            e.status()
+        })?;
+
+        // SAFETY: `base` is freshly allocated and large enough to hold `size_of_image` bytes,
+        // which we zero first so gaps between sections aren't left with stale allocator content.
+        unsafe {
+            core::ptr::write_bytes(base.as_ptr(), 0, size_of_image);
+        }
+
+        let header_size = pe.header.optional_header.map(|oh| oh.windows_fields.size_of_headers as usize).unwrap_or(0);
+        let header_size = header_size.min(kernel_data.len()).min(size_of_image);
+        // SAFETY: `header_size` is bounded by both the source buffer and the destination
+        // allocation, so neither read nor write runs out of bounds.
+        unsafe {
+            core::ptr::copy_nonoverlapping(kernel_data.as_ptr(), base.as_ptr(), header_size);
+        }
+
+        for section in &pe.sections {
+            let dest_off = section.virtual_address as usize;
+            let src_off = section.pointer_to_raw_data as usize;
+            let size = (section.size_of_raw_data as usize)
+                .min(section.virtual_size as usize)
+                .min(kernel_data.len().saturating_sub(src_off));
+            if size == 0 {
+                continue;
+            }
+
+            // SAFETY: `dest_off + size <= size_of_image` because `size_of_image` is the max over
+            // sections of `virtual_address + virtual_size`, and `src_off + size <= kernel_data.len()`.
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    kernel_data[src_off..].as_ptr(),
+                    base.as_ptr().add(dest_off),
+                    size,
+                );
+            }
+        }
+
+        let actual_base = base.as_ptr() as u64;
+        let delta = actual_base.wrapping_sub(image_base);
+        if delta != 0 {
+            // SAFETY: `base` covers `size_of_image` bytes and every relocation target we apply
+            // below was computed from the section/directory layout we just copied in.
+            unsafe {
+                apply_base_relocations(&pe, base, size_of_image, delta)?;
+            }
+        }
+
+        // SAFETY: `base` covers exactly the `size_of_image` bytes we just wrote and relocated.
+        let image = unsafe { core::slice::from_raw_parts(base.as_ptr(), size_of_image) };
+        make_instruction_cache_coherent(image);
+
+        let entry_point = actual_base.wrapping_add(entry_rva as u64) as usize;
+        info!("Loaded PE image at {:#x}, entry point {:#x}", actual_base, entry_point);
+
+        Ok(Self { base, entry_point })
+    }
+
+    /// Jumps to the image's entry point with the standard EFI image entry signature.
+    pub(crate) fn start(&self, handle: Handle) -> Status {
+        let system_table = uefi::table::system_table_boot().expect("boot services have already exited");
+        // SAFETY: `entry_point` was computed in `load` from a successfully relocated image and
+        // matches the `extern "efiapi" fn(Handle, SystemTable<Boot>) -> Status` EFI stub ABI.
+        let entry: EfiImageEntry = unsafe { transmute(self.entry_point) };
+        unsafe { entry(handle, system_table) }
+    }
+}
+
+/// Walks the `.reloc` directory and applies every entry's delta to its target location.
+///
+/// # Safety
+/// `base` must point to `size_of_image` bytes of writable memory containing the image as mapped
+/// by `load`, and every relocation's virtual address must lie within that range.
+unsafe fn apply_base_relocations(pe: &PE, base: NonNull<u8>, size_of_image: usize, delta: u64) -> AppResult<()> {
+    let Some(optional_header) = pe.header.optional_header else {
+        return Ok(());
+    };
+    let Some(directory) = optional_header.data_directories.get_base_relocation_table() else {
+        return Ok(());
+    };
+    if directory.virtual_address == 0 || directory.size == 0 {
+        return Ok(());
+    }
+
+    let mut offset = directory.virtual_address as usize;
+    let end = offset + directory.size as usize;
+    if end > size_of_image {
+        info!("Base relocation directory extends past end of image");
+        return Err(Status::LOAD_ERROR);
+    }
+
+    // SAFETY: caller guarantees `base` covers `size_of_image` bytes.
+    let image = unsafe { core::slice::from_raw_parts(base.as_ptr(), size_of_image) };
+
+    while offset < end {
+        let Some((page_rva, block_size, entries)) = next_reloc_block(image, offset, end)? else {
+            break;
+        };
+
+        for entry in entries.chunks_exact(2) {
+            let entry = u16::from_le_bytes([entry[0], entry[1]]);
+            let reloc_type = entry >> 12;
+            let page_offset = (entry & 0x0fff) as usize;
+            let target_rva = page_rva as usize + page_offset;
+
+            match reloc_type {
+                IMAGE_REL_BASED_ABSOLUTE => {}
+                IMAGE_REL_BASED_HIGHLOW => {
+                    // SAFETY: `target_rva + 4 <= size_of_image`, checked below.
+                    unsafe {
+                        relocate::<4>(base, size_of_image, target_rva, delta)?;
+                    }
+                }
+                IMAGE_REL_BASED_DIR64 => {
+                    // SAFETY: `target_rva + 8 <= size_of_image`, checked below.
+                    unsafe {
+                        relocate::<8>(base, size_of_image, target_rva, delta)?;
+                    }
+                }
+                other => {
+                    info!("Unsupported relocation type {other}");
+                    return Err(Status::LOAD_ERROR);
+                }
+            }
+        }
+
+        offset += block_size;
+    }
+
+    Ok(())
+}
+
+/// Reads the next relocation block header at `offset` within `image[..end]` and returns its page
+/// RVA, its total size (header included), and its entries, or `None` once a zero-size terminator
+/// block is hit. Bounds-checks the header and the block against `end` before indexing `image`,
+/// failing closed with `Status::LOAD_ERROR` rather than panicking on a corrupted directory.
+fn next_reloc_block(image: &[u8], offset: usize, end: usize) -> AppResult<Option<(u32, usize, &[u8])>> {
+    if offset + 8 > end {
+        info!("Base relocation block header runs past the end of the directory");
+        return Err(Status::LOAD_ERROR);
+    }
+    let page_rva = u32::from_le_bytes(image[offset..offset + 4].try_into().unwrap());
+    let block_size = u32::from_le_bytes(image[offset + 4..offset + 8].try_into().unwrap()) as usize;
+    if block_size == 0 {
+        return Ok(None);
+    }
+    if block_size < 8 {
+        info!("Base relocation block is smaller than its own header");
+        return Err(Status::LOAD_ERROR);
+    }
+    if offset + block_size > end {
+        info!("Base relocation block runs past the end of the directory");
+        return Err(Status::LOAD_ERROR);
+    }
+
+    Ok(Some((page_rva, block_size, &image[offset + 8..offset + block_size])))
+}
+
+/// Adds `delta` to the `N`-byte little-endian value at `target_rva` within the image.
+///
+/// # Safety
+/// `base` must point to `size_of_image` bytes of writable memory, and `N` must be 4 or 8.
+unsafe fn relocate<const N: usize>(
+    base: NonNull<u8>,
+    size_of_image: usize,
+    target_rva: usize,
+    delta: u64,
+) -> AppResult<()> {
+    if target_rva + N > size_of_image {
+        info!("Relocation target out of bounds");
+        return Err(Status::LOAD_ERROR);
+    }
+
+    // SAFETY: bounds checked above; caller guarantees `base` covers `size_of_image` bytes.
+    unsafe {
+        let ptr = base.as_ptr().add(target_rva);
+        let mut bytes = [0u8; N];
+        core::ptr::copy_nonoverlapping(ptr, bytes.as_mut_ptr(), N);
+        let value = u64::from_le_bytes(match N {
+            4 => {
+                let mut buf = [0u8; 8];
+                buf[..4].copy_from_slice(&bytes[..4]);
+                buf
+            }
+            _ => bytes[..8].try_into().unwrap(),
+        });
+        let relocated = value.wrapping_add(delta);
+        let relocated_bytes = relocated.to_le_bytes();
+        core::ptr::copy_nonoverlapping(relocated_bytes.as_ptr(), ptr, N);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(page_rva: u32, entries: &[u16]) -> alloc::vec::Vec<u8> {
+        let block_size = 8 + entries.len() * 2;
+        let mut buf = alloc::vec::Vec::with_capacity(block_size);
+        buf.extend_from_slice(&page_rva.to_le_bytes());
+        buf.extend_from_slice(&(block_size as u32).to_le_bytes());
+        for entry in entries {
+            buf.extend_from_slice(&entry.to_le_bytes());
+        }
+        buf
+    }
+
+    #[test]
+    fn next_reloc_block_reads_header_and_entries() {
+        let image = block(0x1000, &[0x3004, 0xa008]);
+        let (page_rva, block_size, entries) = next_reloc_block(&image, 0, image.len()).unwrap().unwrap();
+        assert_eq!(page_rva, 0x1000);
+        assert_eq!(block_size, image.len());
+        assert_eq!(entries, &image[8..]);
+    }
+
+    #[test]
+    fn next_reloc_block_stops_on_zero_size_terminator() {
+        let image = [0u8; 8]; // page_rva = 0, block_size = 0
+        assert_eq!(next_reloc_block(&image, 0, image.len()).unwrap(), None);
+    }
+
+    #[test]
+    fn next_reloc_block_rejects_truncated_header() {
+        let image = [0u8; 4]; // shorter than the 8-byte header
+        assert_eq!(next_reloc_block(&image, 0, image.len()), Err(Status::LOAD_ERROR));
+    }
+
+    #[test]
+    fn next_reloc_block_rejects_oversized_block_size() {
+        let mut image = block(0x1000, &[0x3004]);
+        // Claim a block_size far larger than the directory actually has room for.
+        let huge = u32::MAX;
+        image[4..8].copy_from_slice(&huge.to_le_bytes());
+        assert_eq!(next_reloc_block(&image, 0, image.len()), Err(Status::LOAD_ERROR));
+    }
+
+    #[test]
+    fn next_reloc_block_rejects_header_past_end() {
+        let image = block(0x1000, &[0x3004]);
+        // Start reading where there isn't room left for a full 8-byte header.
+        assert_eq!(next_reloc_block(&image, image.len() - 4, image.len()), Err(Status::LOAD_ERROR));
+    }
+}
@@ -0,0 +1,188 @@
+//! Integrity verification for images fetched over the unauthenticated TFTP/HTTP wire.
+//!
+//! The loader is the trust root for everything it downloads: a kernel or initrd pulled over
+//! plain TFTP or HTTP could have been tampered with in transit, so nothing we fetch is used
+//! until its SHA-256 digest has been checked against a manifest we downloaded first. The
+//! manifest itself travels over that same unauthenticated wire, so it is useless as a trust
+//! anchor unless it is signed: we verify an Ed25519 signature over the manifest body against a
+//! public key baked into the loader at build time before trusting a single digest out of it.
+
+use alloc::vec::Vec;
+
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use log::info;
+use sha2::{Digest, Sha256};
+use uefi::Status;
+
+use crate::AppResult;
+
+pub(crate) type Sha256Digest = [u8; 32];
+
+/// Public half of the manifest signing key, baked in at build time. The corresponding private
+/// key is held by the provisioning infrastructure and never touches the network boot path.
+///
+/// TODO: this is a placeholder key for development; production builds must substitute the real
+/// deployment's signing key before shipping.
+const MANIFEST_SIGNING_KEY: [u8; 32] = [
+    0x3b, 0x6a, 0x27, 0xbc, 0xce, 0xb6, 0xa4, 0x2d, 0x62, 0xa3, 0xa8, 0xd0, 0x2a, 0x6f, 0x0d, 0x73,
+    0x65, 0x32, 0x15, 0x77, 0x1d, 0xe2, 0x43, 0xa6, 0x3a, 0xc0, 0x48, 0xa1, 0x8b, 0x59, 0xda, 0x29,
+];
+
+/// Digests for the images named in a boot manifest, keyed by filename.
+pub(crate) struct Manifest {
+    entries: Vec<(Vec<u8>, Sha256Digest)>,
+}
+
+impl Manifest {
+    /// Parses and authenticates a manifest whose first line is a 64-byte Ed25519 signature (as
+    /// 128 hex characters) over the remaining bytes, which are then `<filename> <hex digest>`
+    /// lines, one per image, e.g.:
+    ///
+    /// ```text
+    /// 3f1c...a9  (128 hex chars: signature over everything below this line)
+    /// bzImage e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855
+    /// initrd  ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad
+    /// ```
+    pub(crate) fn parse(data: &[u8]) -> AppResult<Self> {
+        let newline = data.iter().position(|&b| b == b'\n').ok_or(Status::COMPROMISED_DATA)?;
+        let (sig_line, body) = (&data[..newline], &data[newline + 1..]);
+
+        let sig_bytes = parse_hex_signature(trim(sig_line)).ok_or(Status::COMPROMISED_DATA)?;
+        verify_manifest_signature(body, &sig_bytes)?;
+
+        let mut entries = Vec::new();
+        for line in body.split(|&b| b == b'\n') {
+            let line = trim(line);
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut fields = line.splitn(2, |&b| b == b' ' || b == b'\t');
+            let name = fields.next().unwrap_or(&[]);
+            let hex = trim(fields.next().unwrap_or(&[]));
+            let digest = parse_hex_digest(hex).ok_or(Status::COMPROMISED_DATA)?;
+            entries.push((name.to_vec(), digest));
+        }
+        Ok(Self { entries })
+    }
+
+    /// Looks up the expected digest for `filename`, failing closed if the manifest doesn't
+    /// cover it: an unlisted file is treated the same as a tampered one.
+    pub(crate) fn digest_for(&self, filename: &uefi::CStr8) -> AppResult<&Sha256Digest> {
+        let filename = filename.as_bytes();
+        // `CStr8::as_bytes` includes the terminating null, which manifest names don't carry.
+        let filename = filename.strip_suffix(&[0]).unwrap_or(filename);
+        self.entries
+            .iter()
+            .find(|(name, _)| name.as_slice() == filename)
+            .map(|(_, digest)| digest)
+            .ok_or_else(|| {
+                info!("No manifest entry for {:?}", core::str::from_utf8(filename));
+                Status::COMPROMISED_DATA
+            })
+    }
+}
+
+fn trim(mut s: &[u8]) -> &[u8] {
+    while let [b' ' | b'\t' | b'\r', rest @ ..] = s {
+        s = rest;
+    }
+    while let [rest @ .., b' ' | b'\t' | b'\r'] = s {
+        s = rest;
+    }
+    s
+}
+
+fn parse_hex_digest(hex: &[u8]) -> Option<Sha256Digest> {
+    parse_hex_bytes::<32>(hex)
+}
+
+fn parse_hex_signature(hex: &[u8]) -> Option<[u8; 64]> {
+    parse_hex_bytes::<64>(hex)
+}
+
+fn parse_hex_bytes<const N: usize>(hex: &[u8]) -> Option<[u8; N]> {
+    if hex.len() != N * 2 {
+        return None;
+    }
+    let mut bytes = [0u8; N];
+    for (byte, pair) in bytes.iter_mut().zip(hex.chunks_exact(2)) {
+        *byte = u8::from_str_radix(core::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+/// Verifies the Ed25519 signature over the manifest body against [`MANIFEST_SIGNING_KEY`],
+/// failing closed (invalid key material is treated the same as an invalid signature).
+fn verify_manifest_signature(body: &[u8], sig_bytes: &[u8; 64]) -> AppResult<()> {
+    let key = VerifyingKey::from_bytes(&MANIFEST_SIGNING_KEY).map_err(|_| Status::SECURITY_VIOLATION)?;
+    let signature = Signature::from_bytes(sig_bytes);
+    key.verify(body, &signature).map_err(|_| {
+        info!("Manifest signature verification failed, refusing to trust its digests");
+        Status::SECURITY_VIOLATION
+    })
+}
+
+/// Verifies `data` against `expected`, logging and returning `Status::SECURITY_VIOLATION` on
+/// mismatch so a tampered buffer is never handed to the next boot stage.
+pub(crate) fn verify(label: &str, data: &[u8], expected: &Sha256Digest) -> AppResult<()> {
+    let actual: Sha256Digest = Sha256::digest(data).into();
+    if constant_time_eq(&actual, expected) {
+        info!("{label}: digest verified");
+        Ok(())
+    } else {
+        info!("{label}: digest mismatch, refusing to boot");
+        Err(Status::SECURITY_VIOLATION)
+    }
+}
+
+/// Compares two digests without branching on the mismatching byte, so a difference early in the
+/// digest isn't observable faster than one near the end.
+fn constant_time_eq(a: &Sha256Digest, b: &Sha256Digest) -> bool {
+    let mut diff = 0u8;
+    for (x, y) in a.iter().zip(b.iter()) {
+        diff |= x ^ y;
+    }
+    diff == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rejects_manifest_with_no_newline() {
+        assert_eq!(Manifest::parse(b"not a manifest"), Err(Status::COMPROMISED_DATA));
+    }
+
+    #[test]
+    fn parse_rejects_malformed_signature_line() {
+        // Right shape (has a newline), but the first line isn't 128 hex chars.
+        assert_eq!(Manifest::parse(b"deadbeef\nbzImage e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b85\n"), Err(Status::COMPROMISED_DATA));
+    }
+
+    #[test]
+    fn parse_rejects_forged_signature() {
+        let sig = "00".repeat(64);
+        let body = "bzImage e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b85\n";
+        let data = alloc::format!("{sig}\n{body}");
+        assert_eq!(Manifest::parse(data.as_bytes()), Err(Status::SECURITY_VIOLATION));
+    }
+
+    #[test]
+    fn parse_hex_digest_rejects_wrong_length() {
+        assert_eq!(parse_hex_digest(b"abcd"), None);
+    }
+
+    #[test]
+    fn parse_hex_digest_rejects_non_hex() {
+        let non_hex = [b'z'; 64];
+        assert_eq!(parse_hex_digest(&non_hex), None);
+    }
+
+    #[test]
+    fn trim_strips_leading_and_trailing_whitespace() {
+        assert_eq!(trim(b"  \t hello \r"), b"hello");
+        assert_eq!(trim(b""), b"");
+    }
+}